@@ -1,11 +1,19 @@
 use std::{
-  fs::File,
-  io::{Write, Read},
+  fs::{self, File},
+  io::{self, Write, Read},
   env,
   iter::zip,
+  path::{Path, PathBuf},
   process::Command,
 };
 use regex::Regex;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Editor, Helper};
 
 #[derive(Debug, PartialEq, Clone)]
 enum TokenKind {
@@ -20,8 +28,12 @@ enum TokenKind {
   DefineDirective,
   Ifdef,
   Ifndef,
+  If,
+  Elif,
+  Else,
   Endif,
   Undef,
+  Include,
   EndDefine,
   Newline,
   Stringify,
@@ -30,10 +42,43 @@ enum TokenKind {
   Paste,
 }
 
+#[derive(Debug, Clone)]
+struct Span {
+  line: usize,
+  col: usize,
+}
+
+impl Span {
+  fn synthetic() -> Span {
+    Span { line: 0, col: 0 }
+  }
+}
+
 #[derive(Debug, Clone)]
 struct Token {
   kind: TokenKind,
   value: String,
+  span: Span,
+}
+
+#[derive(Debug, Clone)]
+struct Diagnostic {
+  span: Span,
+  message: String,
+}
+
+impl Diagnostic {
+  fn new(span: Span, message: impl Into<String>) -> Diagnostic {
+    Diagnostic { span, message: message.into() }
+  }
+
+  fn report(&self, file_label: &str) {
+    if self.span.line == 0 {
+      eprintln!("{}: error: {}", file_label, self.message);
+    } else {
+      eprintln!("{}:{}:{}: error: {}", file_label, self.span.line, self.span.col, self.message);
+    }
+  }
 }
 
 #[derive(Debug, Clone)]
@@ -49,7 +94,7 @@ struct FunctionMacro {
   value: Vec<Token>,
 }
 
-fn lex_single_token(input: &String) -> Option<(String, Token)> {
+fn lex_single_token(input: &str, line: usize, col: usize) -> Option<(String, Token)> {
   let regexes = vec![(
     Regex::new(r"^(\.|:)\s*[a-zA-Z_]\w*").unwrap(),
     TokenKind::Property,
@@ -77,6 +122,9 @@ fn lex_single_token(input: &String) -> Option<(String, Token)> {
   ), (
     Regex::new(r#"^"([^"\\]|\\.)*""#).unwrap(),
     TokenKind::String,
+  ), (
+    Regex::new(r#"^#include[ \t]*("[^"\n]*"|<[^>\n]*>)"#).unwrap(),
+    TokenKind::Include,
   ), (
     Regex::new(r"^#define").unwrap(),
     TokenKind::DefineDirective,
@@ -86,6 +134,15 @@ fn lex_single_token(input: &String) -> Option<(String, Token)> {
   ), (
     Regex::new(r"^#ifndef").unwrap(),
     TokenKind::Ifndef,
+  ), (
+    Regex::new(r"^#if").unwrap(),
+    TokenKind::If,
+  ), (
+    Regex::new(r"^#elif").unwrap(),
+    TokenKind::Elif,
+  ), (
+    Regex::new(r"^#else").unwrap(),
+    TokenKind::Else,
   ), (
     Regex::new(r"^#endif").unwrap(),
     TokenKind::Endif,
@@ -96,7 +153,7 @@ fn lex_single_token(input: &String) -> Option<(String, Token)> {
     Regex::new(r"^#end").unwrap(),
     TokenKind::EndDefine,
   ), (
-    Regex::new(r"^[+\-*/!@#$%^&:=~<>?.]+").unwrap(),
+    Regex::new(r"^[+\-*/!@#$%^&|:=~<>?.]+").unwrap(),
     TokenKind::Special,
   ), (
     Regex::new(r"^[()\[\]{}]").unwrap(),
@@ -113,30 +170,47 @@ fn lex_single_token(input: &String) -> Option<(String, Token)> {
       let full = &m[0];
       return Some((
         input[full.len()..].to_string(),
-        Token { kind, value: full.to_string() },
+        Token { kind, value: full.to_string(), span: Span { line, col } },
       ));
     }
   }
   None
 }
 
-fn lex_whole_input(input: &String) -> Option<Vec<Token>> {
+fn advance_position(consumed: &str, line: &mut usize, col: &mut usize) {
+  for ch in consumed.chars() {
+    if ch == '\n' {
+      *line += 1;
+      *col = 1;
+    } else {
+      *col += 1;
+    }
+  }
+}
+
+fn lex_whole_input(input: &str) -> Result<Vec<Token>, Diagnostic> {
   let mut tokens: Vec<Token> = vec![];
-  let mut remaining = input
-    .trim_start_matches(|c| c == ' ' || c == '\t')
-    .to_string();
+  let mut line = 1;
+  let mut col = 1;
+  let trimmed = input.trim_start_matches([' ', '\t']);
+  advance_position(&input[..input.len() - trimmed.len()], &mut line, &mut col);
+  let mut remaining = trimmed.to_string();
   while !remaining.is_empty() {
-    if let Some((rest, token)) = lex_single_token(&remaining) {
+    if let Some((rest, token)) = lex_single_token(&remaining, line, col) {
+      let consumed = &remaining[..remaining.len() - rest.len()];
+      advance_position(consumed, &mut line, &mut col);
       tokens.push(token);
-      remaining = rest
-        .trim_start_matches(|c| c == ' ' || c == '\t')
-        .to_string();
+      let trimmed = rest.trim_start_matches([' ', '\t']);
+      advance_position(&rest[..rest.len() - trimmed.len()], &mut line, &mut col);
+      remaining = trimmed.to_string();
     } else {
-      println!("Tokens: {:?}", tokens);
-      return None;
+      return Err(Diagnostic::new(
+        Span { line, col },
+        format!("unrecognized input starting at {:?}", remaining.chars().take(20).collect::<String>()),
+      ));
     }
   }
-  Some(tokens)
+  Ok(tokens)
 }
 
 fn eval_pastes(tokens: &Vec<Token>) -> Vec<Token> {
@@ -161,6 +235,7 @@ fn eval_pastes(tokens: &Vec<Token>) -> Vec<Token> {
       new_tokens.push(Token {
         kind: TokenKind::Name,
         value: parts.join(""),
+        span: tokens[raw_i - 1].span.clone(),
       });
       raw_i = i + 1;
     } else {
@@ -170,9 +245,312 @@ fn eval_pastes(tokens: &Vec<Token>) -> Vec<Token> {
   new_tokens
 }
 
-fn get_macros(tokens: &Vec<Token>, value_macros: &mut Vec<ValueMacro>, func_macros: &mut Vec<FunctionMacro>) -> Vec<Token> {
+fn undef_macro(name: &str, value_macros: &mut Vec<ValueMacro>, func_macros: &mut Vec<FunctionMacro>) {
+  let mut new_value_macros = vec![];
+  for value_macro in value_macros.clone().into_iter() {
+    if value_macro.name == name {
+      continue;
+    }
+    new_value_macros.push(value_macro.clone());
+  }
+  value_macros.clear();
+  value_macros.extend(new_value_macros);
+  let mut new_func_macros = vec![];
+  for func_macro in func_macros.clone().into_iter() {
+    if func_macro.name == name {
+      continue;
+    }
+    new_func_macros.push(func_macro.clone());
+  }
+  func_macros.clear();
+  func_macros.extend(new_func_macros);
+}
+
+fn is_group_opener(kind: &TokenKind) -> bool {
+  *kind == TokenKind::If || *kind == TokenKind::Ifdef || *kind == TokenKind::Ifndef
+}
+
+// Scans forward from `i` for the next `#elif`/`#else`/`#endif` that belongs to
+// the same group as whatever opened it, skipping over any nested groups.
+fn find_group_end(tokens: &[Token], mut i: usize) -> usize {
+  let mut depth = 0;
+  while i < tokens.len() {
+    if is_group_opener(&tokens[i].kind) {
+      depth += 1;
+    } else if tokens[i].kind == TokenKind::Endif {
+      if depth == 0 {
+        return i;
+      }
+      depth -= 1;
+    } else if depth == 0 && (tokens[i].kind == TokenKind::Elif || tokens[i].kind == TokenKind::Else) {
+      return i;
+    }
+    i += 1;
+  }
+  tokens.len()
+}
+
+// Scans forward from `i` for the `#endif` that closes the group `i` is inside
+// of, ignoring any `#elif`/`#else` belonging to that same group.
+fn find_matching_endif(tokens: &[Token], mut i: usize) -> usize {
+  let mut depth = 0;
+  while i < tokens.len() {
+    if is_group_opener(&tokens[i].kind) {
+      depth += 1;
+    } else if tokens[i].kind == TokenKind::Endif {
+      if depth == 0 {
+        return i;
+      }
+      depth -= 1;
+    }
+    i += 1;
+  }
+  tokens.len()
+}
+
+// Replaces every `defined(NAME)`/`defined NAME` with a `1`/`0` literal before
+// the condition goes through normal macro expansion, so the macro being
+// queried isn't itself expanded away first.
+fn substitute_defined(tokens: &[Token], value_macros: &[ValueMacro], func_macros: &[FunctionMacro]) -> Vec<Token> {
+  let mut result = vec![];
+  let mut i = 0;
+  while i < tokens.len() {
+    if tokens[i].kind == TokenKind::Name && tokens[i].value == "defined" {
+      let has_parens = tokens.get(i + 1).map(|t| t.value.as_str()) == Some("(");
+      let name_idx = if has_parens { i + 2 } else { i + 1 };
+      let closes = !has_parens || tokens.get(name_idx + 1).map(|t| t.value.as_str()) == Some(")");
+      if let (Some(name_tok), true) = (tokens.get(name_idx), closes) {
+        let stripped = name_tok.value.trim_end_matches('!');
+        let found = value_macros.iter().any(|m| m.name.trim_end_matches('!') == stripped)
+          || func_macros.iter().any(|m| m.name.trim_end_matches('!') == stripped);
+        result.push(Token {
+          kind: TokenKind::Number,
+          value: (if found { 1 } else { 0 }).to_string(),
+          span: name_tok.span.clone(),
+        });
+        i = if has_parens { name_idx + 2 } else { name_idx + 1 };
+        continue;
+      }
+    }
+    result.push(tokens[i].clone());
+    i += 1;
+  }
+  result
+}
+
+fn eval_primary(tokens: &[Token], i: &mut usize) -> i64 {
+  match tokens.get(*i) {
+    Some(t) if t.kind == TokenKind::Number => {
+      *i += 1;
+      t.value.parse::<i64>().unwrap_or(0)
+    },
+    Some(t) if t.value.as_str() == "(" => {
+      *i += 1;
+      let value = eval_or(tokens, i);
+      if tokens.get(*i).map(|t| t.value.as_str()) == Some(")") {
+        *i += 1;
+      }
+      value
+    },
+    Some(_) => {
+      *i += 1;
+      0
+    },
+    None => 0,
+  }
+}
+
+fn eval_unary(tokens: &[Token], i: &mut usize) -> i64 {
+  match tokens.get(*i).map(|t| t.value.as_str()) {
+    Some("!") => {
+      *i += 1;
+      if eval_unary(tokens, i) == 0 { 1 } else { 0 }
+    },
+    Some("-") => {
+      *i += 1;
+      -eval_unary(tokens, i)
+    },
+    _ => eval_primary(tokens, i),
+  }
+}
+
+fn eval_mul(tokens: &[Token], i: &mut usize) -> i64 {
+  let mut left = eval_unary(tokens, i);
+  loop {
+    match tokens.get(*i).map(|t| t.value.as_str()) {
+      Some("*") => { *i += 1; left *= eval_unary(tokens, i); },
+      Some("/") => {
+        *i += 1;
+        let right = eval_unary(tokens, i);
+        left = if right != 0 { left / right } else { 0 };
+      },
+      _ => break,
+    }
+  }
+  left
+}
+
+fn eval_add(tokens: &[Token], i: &mut usize) -> i64 {
+  let mut left = eval_mul(tokens, i);
+  loop {
+    match tokens.get(*i).map(|t| t.value.as_str()) {
+      Some("+") => { *i += 1; left += eval_mul(tokens, i); },
+      Some("-") => { *i += 1; left -= eval_mul(tokens, i); },
+      _ => break,
+    }
+  }
+  left
+}
+
+fn eval_rel(tokens: &[Token], i: &mut usize) -> i64 {
+  let mut left = eval_add(tokens, i);
+  loop {
+    match tokens.get(*i).map(|t| t.value.as_str()) {
+      Some("<") => { *i += 1; left = if left < eval_add(tokens, i) { 1 } else { 0 }; },
+      Some("<=") => { *i += 1; left = if left <= eval_add(tokens, i) { 1 } else { 0 }; },
+      Some(">") => { *i += 1; left = if left > eval_add(tokens, i) { 1 } else { 0 }; },
+      Some(">=") => { *i += 1; left = if left >= eval_add(tokens, i) { 1 } else { 0 }; },
+      _ => break,
+    }
+  }
+  left
+}
+
+fn eval_eq(tokens: &[Token], i: &mut usize) -> i64 {
+  let mut left = eval_rel(tokens, i);
+  loop {
+    match tokens.get(*i).map(|t| t.value.as_str()) {
+      Some("==") => { *i += 1; left = if left == eval_rel(tokens, i) { 1 } else { 0 }; },
+      Some("!=") => { *i += 1; left = if left != eval_rel(tokens, i) { 1 } else { 0 }; },
+      _ => break,
+    }
+  }
+  left
+}
+
+fn eval_and(tokens: &[Token], i: &mut usize) -> i64 {
+  let mut left = eval_eq(tokens, i);
+  loop {
+    if tokens.get(*i).map(|t| t.value.as_str()) != Some("&&") {
+      break;
+    }
+    *i += 1;
+    if left == 0 {
+      eval_eq(tokens, i);
+      left = 0;
+    } else {
+      left = if eval_eq(tokens, i) != 0 { 1 } else { 0 };
+    }
+  }
+  left
+}
+
+fn eval_or(tokens: &[Token], i: &mut usize) -> i64 {
+  let mut left = eval_and(tokens, i);
+  loop {
+    if tokens.get(*i).map(|t| t.value.as_str()) != Some("||") {
+      break;
+    }
+    *i += 1;
+    if left != 0 {
+      eval_and(tokens, i);
+      left = 1;
+    } else {
+      left = if eval_and(tokens, i) != 0 { 1 } else { 0 };
+    }
+  }
+  left
+}
+
+// Tracks where `#include` should look for files: the directory of whatever
+// file is currently being expanded, the `-I` search path, and the stack of
+// canonicalized paths still being included (so a cycle can be rejected with
+// a located error instead of recursing forever).
+struct IncludeContext {
+  search_dirs: Vec<String>,
+  current_dir: String,
+  stack: Vec<PathBuf>,
+}
+
+impl IncludeContext {
+  fn new(search_dirs: Vec<String>, current_dir: String) -> IncludeContext {
+    IncludeContext { search_dirs, current_dir, stack: vec![] }
+  }
+}
+
+// Pulls the path and quoting style out of a `#include "path"`/`#include <path>`
+// token's raw value.
+fn parse_include_path(value: &str) -> (String, bool) {
+  let rest = value["#include".len()..].trim();
+  if let Some(path) = rest.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+    (path.to_string(), false)
+  } else {
+    let path = rest.trim_start_matches('<').trim_end_matches('>');
+    (path.to_string(), true)
+  }
+}
+
+// Resolves an `#include` path to a file on disk: quoted includes are tried
+// relative to the including file first, then both forms fall back to the
+// `-I` search directories in order.
+fn resolve_include(path: &str, angled: bool, current_dir: &str, search_dirs: &[String]) -> Option<PathBuf> {
+  if !angled {
+    let candidate = Path::new(current_dir).join(path);
+    if candidate.is_file() {
+      return Some(candidate);
+    }
+  }
+  for dir in search_dirs {
+    let candidate = Path::new(dir).join(path);
+    if candidate.is_file() {
+      return Some(candidate);
+    }
+  }
+  None
+}
+
+fn eval_condition(cond_tokens: Vec<Token>, value_macros: &mut Vec<ValueMacro>, func_macros: &mut Vec<FunctionMacro>, diagnostics: &mut Vec<Diagnostic>, includes: &mut IncludeContext) -> bool {
+  let substituted = substitute_defined(&cond_tokens, value_macros, func_macros);
+  let expanded = get_macros(&substituted, value_macros, func_macros, diagnostics, includes);
+  let mut i = 0;
+  eval_or(&expanded, &mut i) != 0
+}
+
+// Evaluates one `#if`/`#elif` branch starting at `cond_start` (the token right
+// after the directive keyword), falling through to the next `#elif`/`#else`
+// when its condition is false, and returns the rendered tokens of whichever
+// branch was taken along with the index right after the group's `#endif`.
+fn eval_if_branch(tokens: &Vec<Token>, cond_start: usize, value_macros: &mut Vec<ValueMacro>, func_macros: &mut Vec<FunctionMacro>, diagnostics: &mut Vec<Diagnostic>, includes: &mut IncludeContext) -> (Vec<Token>, usize) {
+  let cond_tokens: Vec<Token> = tokens.clone().into_iter()
+    .skip(cond_start)
+    .take_while(|t| t.kind != TokenKind::Newline)
+    .collect();
+  let body_start = cond_start + cond_tokens.len();
+  let next = find_group_end(tokens, body_start);
+  if eval_condition(cond_tokens, value_macros, func_macros, diagnostics, includes) {
+    let body: Vec<Token> = tokens.clone().into_iter().skip(body_start).take(next - body_start).collect();
+    let rendered = eval_pastes(&get_macros(&body, value_macros, func_macros, diagnostics, includes));
+    let endif = find_matching_endif(tokens, next);
+    (rendered, endif + 1)
+  } else {
+    match tokens.get(next).map(|t| t.kind.clone()) {
+      Some(TokenKind::Elif) => eval_if_branch(tokens, next + 1, value_macros, func_macros, diagnostics, includes),
+      Some(TokenKind::Else) => {
+        let else_body_start = next + 1;
+        let endif = find_matching_endif(tokens, else_body_start);
+        let body: Vec<Token> = tokens.clone().into_iter().skip(else_body_start).take(endif - else_body_start).collect();
+        let rendered = eval_pastes(&get_macros(&body, value_macros, func_macros, diagnostics, includes));
+        (rendered, endif + 1)
+      },
+      _ => (vec![], next + 1),
+    }
+  }
+}
+
+fn get_macros(tokens: &Vec<Token>, value_macros: &mut Vec<ValueMacro>, func_macros: &mut Vec<FunctionMacro>, diagnostics: &mut Vec<Diagnostic>, includes: &mut IncludeContext) -> Vec<Token> {
   let mut i = 0;
   let mut new_tokens = vec![];
+  let mut open_ifdef_groups: usize = 0;
   while i < tokens.len() {
     i += 1;
     let token;
@@ -188,46 +566,38 @@ fn get_macros(tokens: &Vec<Token>, value_macros: &mut Vec<ValueMacro>, func_macr
         if name.kind != TokenKind::MacroName {
           continue;
         }
-        let mut new_value_macros = vec![];
-        for value_macro in value_macros.clone().into_iter() {
-          if value_macro.name == name.value {
-            continue;
-          }
-          new_value_macros.push(value_macro.clone());
-        }
-        value_macros.clear();
-        value_macros.extend(new_value_macros);
-        let mut new_func_macros = vec![];
-        for func_macro in func_macros.clone().into_iter() {
-          if func_macro.name == name.value {
-            continue;
-          }
-          new_func_macros.push(func_macro.clone());
-        }
-        func_macros.clear();
-        func_macros.extend(new_func_macros);
+        undef_macro(&name.value, value_macros, func_macros);
       }
     }
     for value_macro in value_macros.clone().into_iter() {
       match apply_value_macro_once(tokens.clone().into_iter().skip(i - 1).collect(), value_macro.clone()) {
         Some(result_tokens) => {
           new_tokens.pop();
-          new_tokens.extend(eval_pastes(&get_macros(&result_tokens, value_macros, func_macros)));
+          new_tokens.extend(eval_pastes(&get_macros(&result_tokens, value_macros, func_macros, diagnostics, includes)));
           break;
         },
         None => {},
       }
     }
+    match apply_builtin_macro_once(tokens.clone().into_iter().skip(i - 1).collect()) {
+      Some((result_tokens, new_i)) => {
+        new_tokens.pop();
+        new_tokens.extend(eval_pastes(&get_macros(&result_tokens, value_macros, func_macros, diagnostics, includes)));
+        i += new_i - 1;
+        continue;
+      },
+      None => {},
+    }
     for func_macro in func_macros.clone().into_iter() {
-      match apply_func_macro_once(tokens.clone().into_iter().skip(i).collect(), func_macro) {
+      match apply_func_macro_once(tokens.clone().into_iter().skip(i).collect(), func_macro, diagnostics, token) {
         Some((result_tokens, new_i)) => {
-          new_tokens.extend(eval_pastes(&get_macros(&result_tokens, value_macros, func_macros)));
+          new_tokens.extend(eval_pastes(&get_macros(&result_tokens, value_macros, func_macros, diagnostics, includes)));
           i += new_i;
           break;
         },
         None => {},
       }
-    } 
+    }
     if token.kind == TokenKind::Ifdef || token.kind == TokenKind::Ifndef {
       if let Some(var) = tokens.into_iter().nth(i) {
         new_tokens.pop();
@@ -257,13 +627,68 @@ fn get_macros(tokens: &Vec<Token>, value_macros: &mut Vec<ValueMacro>, func_macr
               break
             }
           }
+        } else {
+          // Condition held, so the body falls through to the main loop and
+          // this group's own #endif will be visited below like any other
+          // token — remember it's spoken for so that isn't flagged as stray.
+          open_ifdef_groups += 1;
         }
         i += 1;
       }
       continue;
     }
+    if token.kind == TokenKind::If {
+      new_tokens.pop();
+      let (rendered, new_i) = eval_if_branch(tokens, i, value_macros, func_macros, diagnostics, includes);
+      new_tokens.extend(rendered);
+      i = new_i;
+      continue;
+    }
+    if token.kind == TokenKind::Include {
+      new_tokens.pop();
+      let (path, angled) = parse_include_path(&token.value);
+      match resolve_include(&path, angled, &includes.current_dir, &includes.search_dirs) {
+        None => {
+          diagnostics.push(Diagnostic::new(token.span.clone(), format!("cannot find include file {:?}", path)));
+        },
+        Some(resolved) => {
+          let canonical = fs::canonicalize(&resolved).unwrap_or_else(|_| resolved.clone());
+          if includes.stack.contains(&canonical) {
+            diagnostics.push(Diagnostic::new(token.span.clone(), format!("cyclic #include of {:?}", resolved.display())));
+          } else {
+            match fs::read_to_string(&resolved) {
+              Err(e) => diagnostics.push(Diagnostic::new(token.span.clone(), format!("could not read include file {:?}: {}", resolved.display(), e))),
+              Ok(content) => match lex_whole_input(&content) {
+                Err(diagnostic) => diagnostics.push(diagnostic),
+                Ok(included_tokens) => {
+                  includes.stack.push(canonical);
+                  let prev_dir = includes.current_dir.clone();
+                  includes.current_dir = resolved.parent()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_else(|| ".".to_string());
+                  new_tokens.extend(get_macros(&included_tokens, value_macros, func_macros, diagnostics, includes));
+                  includes.current_dir = prev_dir;
+                  includes.stack.pop();
+                },
+              },
+            }
+          }
+        },
+      }
+      continue;
+    }
+    if token.kind == TokenKind::Elif || token.kind == TokenKind::Else {
+      new_tokens.pop();
+      i = find_matching_endif(tokens, i) + 1;
+      continue;
+    }
     if token.kind == TokenKind::Endif {
       new_tokens.pop();
+      if open_ifdef_groups > 0 {
+        open_ifdef_groups -= 1;
+      } else {
+        diagnostics.push(Diagnostic::new(token.span.clone(), "#endif with no matching #if/#ifdef/#ifndef"));
+      }
       continue;
     }
     if token.kind == TokenKind::DefineDirective {
@@ -300,6 +725,9 @@ fn get_macros(tokens: &Vec<Token>, value_macros: &mut Vec<ValueMacro>, func_macr
                 .take_while(|t|
                   t.kind != TokenKind::EndDefine)
                 .collect();
+              if tokens.get(i + 4 + value.len()).is_none() {
+                diagnostics.push(Diagnostic::new(name.span.clone(), format!("#define {} never sees #end", name.value)));
+              }
               let define = FunctionMacro {
                 name: name.value.clone(),
                 params: names,
@@ -328,6 +756,9 @@ fn get_macros(tokens: &Vec<Token>, value_macros: &mut Vec<ValueMacro>, func_macr
             .take_while(|t|
               t.kind != TokenKind::EndDefine)
             .collect();
+          if tokens.get(i + 2 + value.len()).is_none() {
+            diagnostics.push(Diagnostic::new(name.span.clone(), format!("#define {} never sees #end", name.value)));
+          }
           let define = ValueMacro {
             name: name.value.clone(),
             value: value.clone(),
@@ -350,7 +781,7 @@ fn apply_value_macro_once(input: Vec<Token>, value_macro: ValueMacro) -> Option<
     }
     return Some(value_macro.value.clone());
   } else if token.kind == TokenKind::Stringify && token.value[1..token.value.len()-1] == value_macro.name {
-    let tok = Token {kind: TokenKind::String, value: format!("{:?}", render_tokens_as_string(value_macro.value.clone()))};
+    let tok = Token {kind: TokenKind::String, value: format!("{:?}", render_tokens_as_string(value_macro.value.clone())), span: token.span.clone()};
     return Some(vec![tok]);
   } else if token.kind == TokenKind::Delimiter && input.len() > 1 && (input[1].value.as_str() == "__VA_ARGS__" || input[1].value.as_str() == "#...") {
     if value_macro.value.len() != 0 {
@@ -370,7 +801,7 @@ fn apply_value_macros(input: Vec<Token>, value_macro: ValueMacro) -> Vec<Token>
       }
       value_macro.value.clone()
     } else if token.kind == TokenKind::Stringify && token.value[1..token.value.len()-1] == value_macro.name {
-      let tok = Token {kind: TokenKind::String, value: format!("{:?}", render_tokens_as_string(value_macro.value.clone()))};
+      let tok = Token {kind: TokenKind::String, value: format!("{:?}", render_tokens_as_string(value_macro.value.clone())), span: token.span.clone()};
       vec![tok]
     } else if token.kind == TokenKind::Delimiter && i + 1 < input.len() && (input[i + 1].value.as_str() == "__VA_ARGS__" || input[i + 1].value.as_str() == "#...") {
       if value_macro.value.len() != 0 {
@@ -385,68 +816,412 @@ fn apply_value_macros(input: Vec<Token>, value_macro: ValueMacro) -> Vec<Token>
   }).collect()
 }
 
-fn apply_func_macro_once(input: Vec<Token>, func_macro: FunctionMacro) -> Option<(Vec<Token>, usize)> {
-  let tokens = input;
-  let token = tokens.clone().into_iter().nth(0)?;
-  if token.kind == TokenKind::MacroName && token.value == func_macro.name {
-    if let Some(lparen) = tokens.clone().into_iter().nth(1) {
-      let lparen_val = lparen.value.as_str();
-      if lparen_val != "(" && lparen_val != "[" && lparen_val != "{" {
-        return None;
+// Parses a `name!(...)`/`name![...]`/`name!{...}` call at the front of
+// `tokens`, splitting the bracketed body on top-level delimiters. Returns the
+// per-argument token lists and the total number of tokens the call occupies.
+fn parse_macro_call(tokens: &[Token], name: &str) -> Option<(Vec<Vec<Token>>, usize)> {
+  let token = tokens.first().cloned()?;
+  if token.kind != TokenKind::MacroName || token.value != name {
+    return None;
+  }
+  let lparen = tokens.get(1).cloned()?;
+  let lparen_val = lparen.value.as_str();
+  if lparen_val != "(" && lparen_val != "[" && lparen_val != "{" {
+    return None;
+  }
+  let mut nesting_level = 0;
+  let mut args: Vec<Vec<Token>> = vec![vec![]];
+  let mut i = 0;
+  for cur_token in tokens.iter().skip(2).cloned() {
+    i += 1;
+    if cur_token.kind == TokenKind::Delimiter && nesting_level == 0 {
+      args.push(vec![]);
+      continue;
+    }
+    if cur_token.value.as_str() == "(" || cur_token.value.as_str() == "{" || cur_token.value.as_str() == "[" || cur_token.value.as_str() == "function" || cur_token.value.as_str() == "do" || cur_token.value.as_str() == "then" {
+      nesting_level += 1;
+    } else if cur_token.value.as_str() == ")" || cur_token.value.as_str() == "}" || cur_token.value.as_str() == "]" || cur_token.value.as_str() == "end" {
+      nesting_level -= 1;
+      if nesting_level == -1 {
+        break;
+      }
+    }
+    let l = args.len();
+    args[l - 1].push(cur_token);
+  }
+  Some((args, i + 2))
+}
+
+fn apply_func_macro_once(input: Vec<Token>, func_macro: FunctionMacro, diagnostics: &mut Vec<Diagnostic>, call_token: &Token) -> Option<(Vec<Token>, usize)> {
+  let (args, consumed) = parse_macro_call(&input, &func_macro.name)?;
+  let is_variadic = func_macro.params.last().map(|p| p.as_str()) == Some("...");
+  let min_args = if is_variadic { func_macro.params.len() - 1 } else { func_macro.params.len() };
+  let arg_count = if args.len() == 1 && args[0].is_empty() { 0 } else { args.len() };
+  if arg_count < min_args || (!is_variadic && arg_count > func_macro.params.len()) {
+    diagnostics.push(Diagnostic::new(
+      call_token.span.clone(),
+      format!("{} expects {} argument(s), got {}", func_macro.name, func_macro.params.len(), arg_count),
+    ));
+  }
+  let mut value = func_macro.value.clone();
+  let mut k = 0;
+  zip(func_macro.params.clone(), args.clone()).for_each(|(param, arg)| {
+    let val_macro = if param.as_str() == "..." {
+      let varargs = args.clone().into_iter().skip(k);
+      let mut comma_sep = vec![];
+      for (l, arg) in varargs.enumerate() {
+        if l > 0 {
+          comma_sep.push(Token {
+            kind: TokenKind::Delimiter,
+            value: ",".to_string(),
+            span: Span::synthetic(),
+          });
+        }
+        comma_sep.extend(arg);
+      }
+      ValueMacro {
+        name: "__VA_ARGS__".to_string(),
+        value: comma_sep,
+      }
+    } else {
+      ValueMacro {
+        name: param,
+        value: arg,
+      }
+    };
+    value = apply_value_macros(value.clone(), val_macro);
+    k += 1;
+  });
+  Some((value, consumed))
+}
+
+const BUILTIN_MACROS: [&str; 7] = [
+  "subst!", "patsubst!", "filter!", "filterout!", "words!", "word!", "firstword!",
+];
+
+fn render_or_empty(tokens: Vec<Token>) -> String {
+  if tokens.is_empty() {
+    String::new()
+  } else {
+    render_tokens_as_string(tokens)
+  }
+}
+
+// Joins token values with no separating whitespace. A pattern like `src_%` or
+// `%.lua` is lexed as several adjacent tokens (the lexer has no single token
+// kind that spans both a name and `%`), so a single-word argument like a
+// pattern or replacement has to be rejoined tightly rather than through
+// `render_tokens_as_string`, which always inserts spaces between tokens.
+fn render_tight(tokens: Vec<Token>) -> String {
+  tokens.into_iter().map(|t| t.value).collect()
+}
+
+// Splits a token stream into whitespace-separated words, but re-glues tokens
+// that had no whitespace between them in the source (via their spans) back
+// into a single word first. This is `filter!`/`filterout!`'s pattern-list
+// argument: each pattern may itself be a `%`-pattern like `render_tight`
+// handles for a single pattern, but the argument as a whole is a
+// space-separated list of them.
+fn split_into_words(tokens: Vec<Token>) -> Vec<String> {
+  let mut words: Vec<String> = vec![];
+  let mut prev_end: Option<(usize, usize)> = None;
+  for token in tokens {
+    let start = (token.span.line, token.span.col);
+    if prev_end == Some(start) {
+      if let Some(last) = words.last_mut() {
+        last.push_str(&token.value);
       }
-      let mut nesting_level = 0;
-      let mut args: Vec<Vec<Token>> = vec![vec![]];
-      let mut i = 0;
-      for cur_token in tokens.clone().into_iter().skip(2) {
+    } else {
+      words.push(token.value.clone());
+    }
+    prev_end = Some((token.span.line, token.span.col + token.value.chars().count()));
+  }
+  words
+}
+
+// Splits a `%`-pattern into its prefix/suffix halves and, if `word` matches,
+// returns the stem that `%` captured.
+fn pattern_stem(pattern: &str, word: &str) -> Option<String> {
+  match pattern.split_once('%') {
+    Some((prefix, suffix)) => {
+      if word.len() >= prefix.len() + suffix.len() && word.starts_with(prefix) && word.ends_with(suffix) {
+        Some(word[prefix.len()..word.len() - suffix.len()].to_string())
+      } else {
+        None
+      }
+    },
+    None => if word == pattern { Some(String::new()) } else { None },
+  }
+}
+
+fn patsubst_word(pattern: &str, replacement: &str, word: &str) -> String {
+  match pattern_stem(pattern, word) {
+    Some(stem) => match replacement.split_once('%') {
+      Some((prefix, suffix)) => format!("{}{}{}", prefix, stem, suffix),
+      None => replacement.to_string(),
+    },
+    None => word.to_string(),
+  }
+}
+
+fn eval_builtin_macro(name: &str, args: &[Vec<Token>]) -> Option<Vec<Token>> {
+  let word_arg = |n: usize| args.get(n).cloned().map(render_tight).unwrap_or_default();
+  let text_arg = |n: usize| args.get(n).cloned().map(render_or_empty).unwrap_or_default();
+  let result = match name {
+    "subst!" => word_arg(2).replace(&word_arg(0), &word_arg(1)),
+    "patsubst!" => {
+      let (pattern, replacement, text) = (word_arg(0), word_arg(1), text_arg(2));
+      text.split_whitespace()
+        .map(|word| patsubst_word(&pattern, &replacement, word))
+        .collect::<Vec<_>>()
+        .join(" ")
+    },
+    "filter!" => {
+      let patterns = split_into_words(args.first().cloned().unwrap_or_default());
+      text_arg(1).split_whitespace()
+        .filter(|word| patterns.iter().any(|p| pattern_stem(p, word).is_some()))
+        .collect::<Vec<_>>()
+        .join(" ")
+    },
+    "filterout!" => {
+      let patterns = split_into_words(args.first().cloned().unwrap_or_default());
+      text_arg(1).split_whitespace()
+        .filter(|word| !patterns.iter().any(|p| pattern_stem(p, word).is_some()))
+        .collect::<Vec<_>>()
+        .join(" ")
+    },
+    "words!" => text_arg(0).split_whitespace().count().to_string(),
+    "word!" => {
+      let n: usize = word_arg(0).trim().parse().ok()?;
+      let text = text_arg(1);
+      text.split_whitespace().nth(n.checked_sub(1)?).unwrap_or("").to_string()
+    },
+    "firstword!" => text_arg(0).split_whitespace().next().unwrap_or("").to_string(),
+    _ => return None,
+  };
+  lex_whole_input(&result).ok()
+}
+
+fn apply_builtin_macro_once(input: Vec<Token>) -> Option<(Vec<Token>, usize)> {
+  let name = input.clone().into_iter().next()?.value;
+  if !BUILTIN_MACROS.contains(&name.as_str()) {
+    return None;
+  }
+  let (args, consumed) = parse_macro_call(&input, &name)?;
+  let result = eval_builtin_macro(&name, &args)?;
+  Some((result, consumed))
+}
+
+struct CliOptions {
+  input_path: Option<String>,
+  output_path: String,
+  no_format: bool,
+  repl: bool,
+  include_dirs: Vec<String>,
+  value_macros: Vec<ValueMacro>,
+  func_macros: Vec<FunctionMacro>,
+}
+
+fn print_usage() {
+  println!("Usage: luaproc [options] <filename|->");
+  println!("Options:");
+  println!("  -D NAME[=value]   define a macro before lexing, as if by #define");
+  println!("  -U NAME           undefine a macro before lexing");
+  println!("  -o <file>         write output to <file> (default: out.lua)");
+  println!("  --no-format       skip formatting the output with stylua");
+  println!("  --repl            start an interactive REPL instead of processing a file");
+  println!("  -I <dir>          add a directory to the #include search path");
+  println!("  -                 read the source from stdin");
+}
+
+fn parse_cli_define(definition: &str) -> Option<ValueMacro> {
+  let name = format!("{}!", definition.split('=').next()?);
+  match definition.split_once('=') {
+    Some((_, value)) => {
+      let value = lex_whole_input(&value.to_string()).ok()?;
+      Some(ValueMacro { name, value })
+    },
+    None => Some(ValueMacro { name, value: vec![] }),
+  }
+}
+
+fn parse_args(args: &[String]) -> Option<CliOptions> {
+  let mut opts = CliOptions {
+    input_path: None,
+    output_path: "out.lua".to_string(),
+    no_format: false,
+    repl: false,
+    include_dirs: vec![],
+    value_macros: vec![],
+    func_macros: vec![],
+  };
+  let mut positional_set = false;
+  let mut i = 1;
+  while i < args.len() {
+    let arg = args[i].as_str();
+    match arg {
+      "-D" => {
         i += 1;
-        if cur_token.kind == TokenKind::Delimiter && nesting_level == 0 {
-          args.push(vec![]);
+        let definition = args.get(i)?;
+        let value_macro = parse_cli_define(definition)?;
+        opts.value_macros.push(value_macro);
+      },
+      "-U" => {
+        i += 1;
+        let name = args.get(i)?;
+        undef_macro(&format!("{}!", name), &mut opts.value_macros, &mut opts.func_macros);
+      },
+      "-o" => {
+        i += 1;
+        opts.output_path = args.get(i)?.clone();
+      },
+      "-I" => {
+        i += 1;
+        opts.include_dirs.push(args.get(i)?.clone());
+      },
+      "--no-format" => opts.no_format = true,
+      "--repl" => {
+        opts.repl = true;
+        positional_set = true;
+      },
+      "-" => {
+        opts.input_path = None;
+        positional_set = true;
+      },
+      _ => {
+        opts.input_path = Some(arg.to_string());
+        positional_set = true;
+      },
+    }
+    i += 1;
+  }
+  if !positional_set {
+    return None;
+  }
+  Some(opts)
+}
+
+// Refuses to submit a line while it has an open brace/paren/bracket or an
+// unclosed `#define ... #end` / `#if ... #endif` region, so multi-line macro
+// definitions can be typed across several prompts before expansion runs.
+fn is_balanced(input: &str) -> bool {
+  let tokens = match lex_whole_input(&input.to_string()) {
+    Ok(tokens) => tokens,
+    Err(_) => return false,
+  };
+  let mut brace_depth: i64 = 0;
+  let mut define_depth: i64 = 0;
+  let mut cond_depth: i64 = 0;
+  for token in &tokens {
+    match token.kind {
+      TokenKind::Brace => match token.value.as_str() {
+        "(" | "[" | "{" => brace_depth += 1,
+        ")" | "]" | "}" => brace_depth -= 1,
+        _ => {},
+      },
+      TokenKind::DefineDirective => define_depth += 1,
+      TokenKind::EndDefine => define_depth -= 1,
+      TokenKind::If | TokenKind::Ifdef | TokenKind::Ifndef => cond_depth += 1,
+      TokenKind::Endif => cond_depth -= 1,
+      _ => {},
+    }
+  }
+  brace_depth <= 0 && define_depth <= 0 && cond_depth <= 0
+}
+
+struct ReplValidator;
+
+impl Validator for ReplValidator {
+  fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+    if is_balanced(ctx.input()) {
+      Ok(ValidationResult::Valid(None))
+    } else {
+      Ok(ValidationResult::Incomplete)
+    }
+  }
+}
+
+struct ReplHelper {
+  validator: ReplValidator,
+}
+
+impl Completer for ReplHelper {
+  type Candidate = String;
+}
+
+impl Hinter for ReplHelper {
+  type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {
+  fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+    self.validator.validate(ctx)
+  }
+}
+
+impl Helper for ReplHelper {}
+
+// Lists the macros currently defined in this REPL session.
+fn print_macro_table(value_macros: &[ValueMacro], func_macros: &[FunctionMacro]) {
+  for value_macro in value_macros {
+    println!("{}", value_macro.name);
+  }
+  for func_macro in func_macros {
+    println!("{}({})", func_macro.name, func_macro.params.join(", "));
+  }
+}
+
+fn run_repl(value_macros: &mut Vec<ValueMacro>, func_macros: &mut Vec<FunctionMacro>, include_dirs: Vec<String>) {
+  let mut includes = IncludeContext::new(include_dirs, ".".to_string());
+  let mut editor: Editor<ReplHelper, DefaultHistory> = match Editor::new() {
+    Ok(editor) => editor,
+    Err(e) => {
+      eprintln!("Could not start REPL: {}", e);
+      return;
+    },
+  };
+  editor.set_helper(Some(ReplHelper { validator: ReplValidator }));
+  println!("luaproc repl -- :macros lists definitions, :undef NAME removes one, Ctrl-D quits");
+  loop {
+    match editor.readline("> ") {
+      Ok(line) => {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
           continue;
         }
-        if cur_token.value.as_str() == "(" || cur_token.value.as_str() == "{" || cur_token.value.as_str() == "[" || cur_token.value.as_str() == "function" || cur_token.value.as_str() == "do" || cur_token.value.as_str() == "then" {
-          nesting_level += 1;
-        } else if cur_token.value.as_str() == ")" || cur_token.value.as_str() == "}" || cur_token.value.as_str() == "]" || cur_token.value.as_str() == "end" {
-          nesting_level -= 1;
-          if nesting_level == -1 {
-            break;
-          }
+        let _ = editor.add_history_entry(line.as_str());
+        if trimmed == ":macros" {
+          print_macro_table(value_macros, func_macros);
+          continue;
         }
-        let l = args.clone().len();
-        args[l - 1].push(cur_token);
-      }
-      let mut value = func_macro.value.clone();
-      let mut k = 0;
-      zip(func_macro.params.clone(), args.clone()).for_each(|(param, arg)| {
-        let val_macro;
-        if param.as_str() == "..." {
-          let varargs = args.clone().into_iter().skip(k);
-          let mut comma_sep = vec![];
-          for (l, arg) in varargs.enumerate() {
-            if l > 0 {
-              comma_sep.push(Token {
-                kind: TokenKind::Delimiter,
-                value: ",".to_string(),
-              });
+        if let Some(name) = trimmed.strip_prefix(":undef ") {
+          undef_macro(&format!("{}!", name.trim()), value_macros, func_macros);
+          continue;
+        }
+        match lex_whole_input(&line) {
+          Err(diagnostic) => diagnostic.report("<repl>"),
+          Ok(tokens) => {
+            let mut diagnostics = vec![];
+            let expanded = get_macros(&tokens, value_macros, func_macros, &mut diagnostics, &mut includes);
+            for diagnostic in &diagnostics {
+              diagnostic.report("<repl>");
             }
-            comma_sep.extend(arg);
-          }
-          val_macro = ValueMacro {
-            name: "__VA_ARGS__".to_string(),
-            value: comma_sep,
-          }
-        } else {
-          val_macro = ValueMacro {
-            name: param,
-            value: arg,
-          };
+            if !expanded.is_empty() {
+              println!("{}", render_tokens_as_string(expanded));
+            }
+          },
         }
-        value = apply_value_macros(value.clone(), val_macro);
-        k += 1;
-      });
-      return Some((value.clone(), i + 2));
+      },
+      Err(ReadlineError::Interrupted) => continue,
+      Err(ReadlineError::Eof) => break,
+      Err(e) => {
+        eprintln!("Readline error: {}", e);
+        break;
+      },
     }
   }
-  None
 }
 
 fn render_tokens_as_string(tokens: Vec<Token>) -> String {
@@ -459,51 +1234,87 @@ fn render_tokens_as_string(tokens: Vec<Token>) -> String {
         acc + " " + &v
       }
     }).to_string())
-    .unwrap()
+    .unwrap_or_default()
 }
 
 fn main() {
   let args: Vec<_> = env::args().collect();
-  if args.len() < 2 {
-    println!("Usage: luaproc <filename>");
+  let mut opts = match parse_args(&args) {
+    Some(opts) => opts,
+    None => {
+      print_usage();
+      return;
+    },
+  };
+  if opts.repl {
+    run_repl(&mut opts.value_macros, &mut opts.func_macros, opts.include_dirs.clone());
     return;
   }
-  let file_path = args[1].clone();
-  let file_res = File::open(file_path);
   let input;
-  match file_res {
-    Err(e) => {
-      eprintln!("Could not open file: {}", e);
-      return;
-    },
-    Ok(mut f) => {
+  match &opts.input_path {
+    None => {
       let mut content = String::new();
-      match f.read_to_string(&mut content) {
+      match io::stdin().read_to_string(&mut content) {
         Err(e) => {
-          eprintln!("Could not read from file: {}", e);
+          eprintln!("Could not read from stdin: {}", e);
           return;
         },
         Ok(_) => input = content,
       }
     },
+    Some(file_path) => {
+      let file_res = File::open(file_path);
+      match file_res {
+        Err(e) => {
+          eprintln!("Could not open file: {}", e);
+          return;
+        },
+        Ok(mut f) => {
+          let mut content = String::new();
+          match f.read_to_string(&mut content) {
+            Err(e) => {
+              eprintln!("Could not read from file: {}", e);
+              return;
+            },
+            Ok(_) => input = content,
+          }
+        },
+      }
+    },
   }
   let backslash_re = Regex::new(r"\\\r?\n").unwrap();
   let input = backslash_re.replace_all(&input, "").to_string();
-  let result = lex_whole_input(&input);
-  let tokens;
-  match result {
-    None => {
-      eprintln!("Tokenization Failed");
+  let file_label = opts.input_path.clone().unwrap_or_else(|| "<stdin>".to_string());
+  let tokens = match lex_whole_input(&input) {
+    Err(diagnostic) => {
+      diagnostic.report(&file_label);
       return;
     },
-    Some(ts) => tokens = ts
+    Ok(ts) => ts,
+  };
+  let mut value_macros = opts.value_macros.clone();
+  let mut func_macros = opts.func_macros.clone();
+  let mut diagnostics = vec![];
+  let current_dir = opts.input_path.as_ref()
+    .and_then(|p| Path::new(p).parent())
+    .map(|p| p.to_string_lossy().to_string())
+    .filter(|p| !p.is_empty())
+    .unwrap_or_else(|| ".".to_string());
+  let mut includes = IncludeContext::new(opts.include_dirs.clone(), current_dir);
+  if let Some(file_path) = &opts.input_path {
+    if let Ok(canonical) = fs::canonicalize(file_path) {
+      includes.stack.push(canonical);
+    }
+  }
+  let tokens = get_macros(&tokens, &mut value_macros, &mut func_macros, &mut diagnostics, &mut includes);
+  for diagnostic in &diagnostics {
+    diagnostic.report(&file_label);
   }
-  let tokens = get_macros(&tokens, &mut vec![], &mut vec![]);
-  let result = render_tokens_as_string(tokens);
-  let out = File::create("out.lua");
+  let result = if tokens.is_empty() { String::new() } else { render_tokens_as_string(tokens) };
+  let out = File::create(&opts.output_path);
   match out {
     Err(e) => {
-      eprintln!("Could not create out.lua: {}", e);
+      eprintln!("Could not create {}: {}", opts.output_path, e);
       return;
     },
     Ok(mut f) => {
@@ -513,8 +1324,11 @@ fn main() {
       }
     }
   }
+  if opts.no_format {
+    return;
+  }
   match Command::new("stylua")
-    .arg("out.lua")
+    .arg(&opts.output_path)
     .output() {
     Err(e) => {
       eprintln!("Could not format with stylua: {}", e);
@@ -524,3 +1338,188 @@ fn main() {
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn expand(src: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let tokens = lex_whole_input(&src.to_string()).expect("lexing should succeed");
+    let mut value_macros = vec![];
+    let mut func_macros = vec![];
+    let mut diagnostics = vec![];
+    let mut includes = IncludeContext::new(vec![], ".".to_string());
+    let result = get_macros(&tokens, &mut value_macros, &mut func_macros, &mut diagnostics, &mut includes);
+    (result, diagnostics)
+  }
+
+  #[test]
+  fn if_true_branch_is_kept() {
+    let (tokens, diagnostics) = expand("#if 1 + 1 == 2\nok\n#endif\n");
+    assert!(diagnostics.is_empty());
+    assert_eq!(render_tokens_as_string(tokens).trim(), "ok");
+  }
+
+  #[test]
+  fn if_false_branch_falls_to_else() {
+    let (tokens, diagnostics) = expand("#if 0\nyes\n#else\nno\n#endif\n");
+    assert!(diagnostics.is_empty());
+    assert_eq!(render_tokens_as_string(tokens).trim(), "no");
+  }
+
+  #[test]
+  fn builtin_macro_call_as_first_token_is_expanded() {
+    let (tokens, diagnostics) = expand("firstword!(hello world)");
+    assert!(diagnostics.is_empty());
+    assert_eq!(render_tokens_as_string(tokens), "hello");
+  }
+
+  #[test]
+  fn patsubst_substitutes_percent_stem() {
+    let (tokens, diagnostics) = expand("patsubst!(src_%, get_%, src_x src_y)");
+    assert!(diagnostics.is_empty());
+    assert_eq!(render_tokens_as_string(tokens), "get_x get_y");
+  }
+
+  #[test]
+  fn filter_keeps_only_matching_words() {
+    let (tokens, diagnostics) = expand("filter!(src_%, src_x lib_x src_y)");
+    assert!(diagnostics.is_empty());
+    assert_eq!(render_tokens_as_string(tokens), "src_x src_y");
+  }
+
+  #[test]
+  fn pattern_without_percent_requires_an_exact_match() {
+    let (tokens, diagnostics) = expand("filter!(foo, foo foobar bar)");
+    assert!(diagnostics.is_empty());
+    assert_eq!(render_tokens_as_string(tokens), "foo");
+  }
+
+  #[test]
+  fn patsubst_leaves_non_matching_words_untouched_without_percent() {
+    let (tokens, diagnostics) = expand("patsubst!(foo, bar, foo foobar bar)");
+    assert!(diagnostics.is_empty());
+    assert_eq!(render_tokens_as_string(tokens), "bar foobar bar");
+  }
+
+  #[test]
+  fn stray_endif_is_reported_with_a_location() {
+    let (_, diagnostics) = expand("#endif\n");
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.contains("no matching"));
+    assert_eq!(diagnostics[0].span.line, 1);
+  }
+
+  #[test]
+  fn func_macro_arity_mismatch_is_reported() {
+    let (_, diagnostics) = expand("#define add!(a, b) = a + b #end\nadd!(1)\n");
+    assert!(diagnostics.iter().any(|d| d.message.contains("expects")));
+  }
+
+  #[test]
+  fn define_never_seeing_end_reports_instead_of_panicking() {
+    let (tokens, diagnostics) = expand("#define X! = 1\nprint(\"after\")\n");
+    assert!(diagnostics.iter().any(|d| d.message.contains("never sees #end")));
+    assert_eq!(render_tokens_as_string(tokens), "");
+  }
+
+  #[test]
+  fn parse_cli_define_with_value_lexes_the_value() {
+    let value_macro = parse_cli_define("FOO=1 + 1").unwrap();
+    assert_eq!(value_macro.name, "FOO!");
+    assert_eq!(render_tokens_as_string(value_macro.value), "1 + 1");
+  }
+
+  #[test]
+  fn parse_cli_define_without_value_defines_an_empty_macro() {
+    let value_macro = parse_cli_define("FOO").unwrap();
+    assert_eq!(value_macro.name, "FOO!");
+    assert!(value_macro.value.is_empty());
+  }
+
+  #[test]
+  fn parse_args_reads_define_output_and_input_path() {
+    let args: Vec<String> = ["luaproc", "-D", "FOO=1", "-o", "out.lua", "in.lua"]
+      .iter().map(|s| s.to_string()).collect();
+    let opts = parse_args(&args).unwrap();
+    assert_eq!(opts.input_path, Some("in.lua".to_string()));
+    assert_eq!(opts.output_path, "out.lua");
+    assert_eq!(opts.value_macros.len(), 1);
+    assert_eq!(opts.value_macros[0].name, "FOO!");
+  }
+
+  #[test]
+  fn parse_args_dash_reads_from_stdin() {
+    let args: Vec<String> = ["luaproc", "-"].iter().map(|s| s.to_string()).collect();
+    let opts = parse_args(&args).unwrap();
+    assert_eq!(opts.input_path, None);
+  }
+
+  #[test]
+  fn parse_args_without_a_positional_or_repl_flag_fails() {
+    let args: Vec<String> = ["luaproc", "--no-format"].iter().map(|s| s.to_string()).collect();
+    assert!(parse_args(&args).is_none());
+  }
+
+  #[test]
+  fn is_balanced_accepts_a_complete_line() {
+    assert!(is_balanced("print(1 + 1)"));
+  }
+
+  #[test]
+  fn is_balanced_rejects_an_open_paren() {
+    assert!(!is_balanced("print(1 + 1"));
+  }
+
+  #[test]
+  fn is_balanced_rejects_a_define_missing_end() {
+    assert!(!is_balanced("#define square!(x) = x * x"));
+  }
+
+  #[test]
+  fn is_balanced_accepts_a_define_with_end() {
+    assert!(is_balanced("#define square!(x) = x * x #end"));
+  }
+
+  #[test]
+  fn is_balanced_rejects_an_if_missing_endif() {
+    assert!(!is_balanced("#if 1\nok"));
+  }
+
+  #[test]
+  fn parse_include_path_unwraps_a_quoted_path() {
+    assert_eq!(parse_include_path(r#"#include "foo.lua""#), ("foo.lua".to_string(), false));
+  }
+
+  #[test]
+  fn parse_include_path_unwraps_an_angled_path() {
+    assert_eq!(parse_include_path("#include <foo.lua>"), ("foo.lua".to_string(), true));
+  }
+
+  #[test]
+  fn resolve_include_prefers_current_dir_for_quoted_includes() {
+    let dir = env::temp_dir().join("luaproc_test_resolve_include_quoted");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("foo.lua"), "").unwrap();
+    let dir_str = dir.to_string_lossy().to_string();
+    let resolved = resolve_include("foo.lua", false, &dir_str, &[]);
+    assert_eq!(resolved, Some(dir.join("foo.lua")));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn resolve_include_falls_back_to_search_dirs() {
+    let dir = env::temp_dir().join("luaproc_test_resolve_include_search_dir");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("foo.lua"), "").unwrap();
+    let dir_str = dir.to_string_lossy().to_string();
+    let resolved = resolve_include("foo.lua", true, ".", &[dir_str]);
+    assert_eq!(resolved, Some(dir.join("foo.lua")));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn resolve_include_returns_none_when_not_found() {
+    assert_eq!(resolve_include("does_not_exist.lua", false, ".", &[]), None);
+  }
+}